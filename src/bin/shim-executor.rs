@@ -1,10 +1,39 @@
-use std::{fs::File, io::Write, path::PathBuf, process::Command};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
 
 use clap::{command, Parser};
-use gevulot_shim::{Task, TaskResult, TASK_FILE_NAME, TASK_RESULT_FILE_NAME};
+use gevulot_shim::cpu_pin;
+use gevulot_shim::qemu_config::{BuildContext, QemuCommandBuilder, QemuProfile};
+use gevulot_shim::qmp::{self, QMP_SOCKET_NAME};
+use gevulot_shim::result_data::TASK_RESULT_DATA_FILE_NAME;
+use gevulot_shim::vfio;
+use gevulot_shim::{
+    Task, TaskResult, TASK_FILE_NAME, TASK_RESULT_FILE_NAME, WORKSPACE_PMEM_FILE_NAME,
+    WORKSPACE_PMEM_LABEL,
+};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+/// Features enabled in the built-in default profile; matches the device
+/// topology `run_qemu` used to hardcode unconditionally. These stay enabled
+/// in `--pmem` mode too, since the program image keeps booting from the
+/// `scsi` drive regardless of which channel carries the workspace.
+const DEFAULT_FEATURES: &[&str] = &["rng", "scsi", "serial"];
+
+/// memory-backend-file (and the virtio-pmem NVDIMM it backs) requires its
+/// backing file be aligned to this boundary; QEMU refuses to start
+/// otherwise.
+const PMEM_ALIGNMENT: u64 = 2 * 1024 * 1024;
+
+fn align_up(size: u64, alignment: u64) -> u64 {
+    size.div_ceil(alignment) * alignment
+}
+
 #[derive(Clone, Debug, Parser)]
 #[command(author, version, about = "Gevulot Shim Executor")]
 pub struct Config {
@@ -37,6 +66,37 @@ pub struct Config {
 
     pub program: PathBuf,
 
+    #[arg(
+        long,
+        long_help = "Maximum time in seconds the task is allowed to run before it is forcibly terminated"
+    )]
+    pub timeout: Option<u64>,
+
+    #[arg(
+        long,
+        long_help = "Path to a TOML file describing the QEMU device topology; defaults to the built-in profile"
+    )]
+    pub qemu_config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        long_help = "Expose the workspace as a virtio-pmem DAX device instead of virtfs; the program image stays on virtio-scsi"
+    )]
+    pub pmem: bool,
+
+    #[arg(
+        default_value_t = 1024,
+        long,
+        long_help = "Size in MBs of the raw file backing the workspace pmem device, used only with --pmem"
+    )]
+    pub workspace_pmem_size: u32,
+
+    #[arg(
+        long,
+        long_help = "Host core list (e.g. 0,2,4-7) to pin vCPU threads to, one core per vCPU in order"
+    )]
+    pub cpu_pin: Option<String>,
+
     #[arg(last = true, help = "Program args")]
     pub args: Vec<String>,
 }
@@ -76,6 +136,139 @@ fn pre_check(config: &Config) -> Result<()> {
     Ok(())
 }
 
+/// Owns the GPU vfio-pci bindings made before launch and restores their
+/// original drivers on drop, so a GPU isn't left bound to vfio-pci if
+/// `run_qemu` returns early via `?` (cpu-pin failure, a wait error, pmem
+/// result extraction failure, ...) instead of reaching its happy path.
+struct GpuBindings(Vec<vfio::OriginalBinding>);
+
+impl Drop for GpuBindings {
+    fn drop(&mut self) {
+        for binding in &self.0 {
+            if let Err(e) = binding.restore() {
+                eprintln!("failed to restore original driver binding: {e}");
+            }
+        }
+    }
+}
+
+/// Run `cmd`, returning an error naming `what` if it doesn't exit
+/// successfully.
+fn run_checked(mut cmd: Command, what: &str) -> Result<()> {
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(format!("{what} failed with {status}").into());
+    }
+    Ok(())
+}
+
+/// Build the raw file backing the workspace's virtio-pmem device: an
+/// ext4 filesystem (labeled `WORKSPACE_PMEM_LABEL` so the guest can find it
+/// regardless of `/dev/pmemN` enumeration order) containing the task
+/// descriptor and the files it references, since virtfs is no longer
+/// available to share them directly. Returns the aligned image size.
+fn build_pmem_workspace_image(
+    image_path: &Path,
+    min_size: u64,
+    workspace: &Path,
+    task: &Task,
+) -> Result<u64> {
+    let aligned_size = align_up(min_size, PMEM_ALIGNMENT);
+
+    let file = File::options().read(true).write(true).create(true).open(image_path)?;
+    file.set_len(aligned_size)?;
+    drop(file);
+
+    run_checked(
+        {
+            let mut cmd = Command::new("mkfs.ext4");
+            cmd.args(["-F", "-q", "-L", WORKSPACE_PMEM_LABEL]);
+            cmd.arg(image_path);
+            cmd
+        },
+        "mkfs.ext4 on workspace pmem image",
+    )?;
+
+    let mount_point = workspace.join(".pmem-mount");
+    std::fs::create_dir_all(&mount_point)?;
+    run_checked(
+        {
+            let mut cmd = Command::new("mount");
+            cmd.args(["-o", "loop"]).arg(image_path).arg(&mount_point);
+            cmd
+        },
+        "loop-mounting workspace pmem image",
+    )?;
+
+    let populate = (|| -> Result<()> {
+        let mut task_file = File::create(mount_point.join(TASK_FILE_NAME))?;
+        serde_json::to_writer(&mut task_file, task)?;
+        task_file.flush()?;
+
+        let workspace = workspace.to_str().expect("workspace path is valid utf-8");
+        for (name, path) in task.get_task_files_path(workspace) {
+            std::fs::copy(&path, mount_point.join(name))?;
+        }
+
+        Ok(())
+    })();
+
+    // Always try to unmount, but don't let a clean unmount mask a populate
+    // error that left the image half-written.
+    let unmount = run_checked(
+        {
+            let mut cmd = Command::new("umount");
+            cmd.arg(&mount_point);
+            cmd
+        },
+        "unmounting workspace pmem image",
+    );
+    populate?;
+    unmount?;
+
+    Ok(aligned_size)
+}
+
+/// After the guest exits, loop-mount the workspace pmem image back on the
+/// host and copy `task_result.json` (and `task_result.bin`, if present)
+/// into `workspace`, so the existing virtfs-style read-back path can find
+/// them without caring whether `--pmem` was used.
+fn extract_pmem_workspace_results(image_path: &Path, workspace: &Path) -> Result<()> {
+    let mount_point = workspace.join(".pmem-mount");
+    std::fs::create_dir_all(&mount_point)?;
+    run_checked(
+        {
+            let mut cmd = Command::new("mount");
+            cmd.args(["-o", "loop"]).arg(image_path).arg(&mount_point);
+            cmd
+        },
+        "loop-mounting workspace pmem image",
+    )?;
+
+    let extract = (|| -> Result<()> {
+        for name in [TASK_RESULT_FILE_NAME, TASK_RESULT_DATA_FILE_NAME] {
+            let src = mount_point.join(name);
+            if src.exists() {
+                std::fs::copy(&src, workspace.join(name))?;
+            }
+        }
+        Ok(())
+    })();
+
+    let unmount = run_checked(
+        {
+            let mut cmd = Command::new("umount");
+            cmd.arg(&mount_point);
+            cmd
+        },
+        "unmounting workspace pmem image",
+    );
+    extract?;
+    unmount?;
+
+    Ok(())
+}
+
 fn run_qemu(config: Config) -> Result<TaskResult> {
     // Task descriptor.
     let task = Task {
@@ -84,73 +277,133 @@ fn run_qemu(config: Config) -> Result<TaskResult> {
         files: config.file,
     };
 
-    let mut task_file = File::create(config.workspace.join(TASK_FILE_NAME))?;
-    serde_json::to_writer(&mut task_file, &task)?;
-    task_file.flush()?;
-    drop(task_file);
+    // In virtfs mode the guest reads task.json straight out of the shared
+    // workspace directory, so it's written there directly. In pmem mode
+    // that directory isn't shared with the guest at all, so the task
+    // descriptor (and its files) are written into the workspace pmem image
+    // below instead.
+    if !config.pmem {
+        let mut task_file = File::create(config.workspace.join(TASK_FILE_NAME))?;
+        serde_json::to_writer(&mut task_file, &task)?;
+        task_file.flush()?;
+    }
+
+    let qmp_socket_path = config.workspace.join(QMP_SOCKET_NAME);
+
+    // The program image always stays on its normal bootable drive: a
+    // virtio-pmem NVDIMM isn't a bootable bus under the default
+    // SeaBIOS/no-`-kernel` topology, so only the workspace channel moves to
+    // pmem. `--pmem` therefore still enables the `scsi` feature below.
+    let workspace_pmem_file = config.workspace.join(WORKSPACE_PMEM_FILE_NAME);
+    let workspace_pmem_min_size = u64::from(config.workspace_pmem_size) * 1024 * 1024;
+    let workspace_size = if config.pmem {
+        build_pmem_workspace_image(&workspace_pmem_file, workspace_pmem_min_size, &config.workspace, &task)?
+    } else {
+        0
+    };
+
+    let profile = match &config.qemu_config {
+        Some(path) => QemuProfile::load(path)?,
+        None => QemuProfile::default_profile(),
+    };
+    let builder = QemuCommandBuilder::new(profile);
+    let build_ctx = BuildContext {
+        program: config.program.to_str().expect("program path is valid utf-8"),
+        workspace: config.workspace.to_str().expect("workspace path is valid utf-8"),
+        smp: config.smp,
+        mem: config.mem,
+        pmem: config.pmem,
+        workspace_pmem_file: workspace_pmem_file.to_str().expect("workspace pmem path is valid utf-8"),
+        workspace_size,
+    };
+    let device_args = builder.build(DEFAULT_FEATURES, &build_ctx)?;
+
+    // Bind each passthrough GPU to vfio-pci before QEMU touches it; the
+    // original driver is restored (via `GpuBindings`'s `Drop`) whenever this
+    // function returns, on the happy path or any early error return alike.
+    let _gpu_bindings = GpuBindings(
+        config
+            .gpu
+            .iter()
+            .map(|addr| vfio::bind_vfio(addr))
+            .collect::<Result<_>>()?,
+    );
 
     // run qemu
     let mut cmd = Command::new("qemu-system-x86_64");
-    cmd.args(["-machine", "q35"])
-        .args([
-            "-device",
-            "pcie-root-port,port=0x10,chassis=1,id=pci.1,bus=pcie.0,multifunction=on,addr=0x3",
-        ])
-        .args([
-            "-device",
-            "pcie-root-port,port=0x11,chassis=2,id=pci.2,bus=pcie.0,addr=0x3.0x1",
-        ])
-        .args([
-            "-device",
-            "pcie-root-port,port=0x12,chassis=3,id=pci.3,bus=pcie.0,addr=0x3.0x2",
-        ])
-        // Register 2 hard drives via SCSI
-        .args(["-device", "virtio-scsi-pci,bus=pci.2,addr=0x0,id=scsi0"])
-        .args(["-device", "scsi-hd,bus=scsi0.0,drive=hd0"])
-        .args(["-vga", "none"])
-        // CPUS
-        .args(["-smp", &config.smp.to_string()])
-        .args(["-device", "isa-debug-exit"])
-        // MEMORY
-        .args(["-m", &format!("{}M", config.mem)])
-        .args(["-device", "virtio-rng-pci"])
-        .args(["-machine", "accel=kvm:tcg"])
-        .args(["-cpu", "max"])
-        // IMAGE FILE
-        .args([
-            "-drive",
-            &format!(
-                "file={},format=raw,if=none,id=hd0,readonly=on",
-                &config
-                    .program
-                    .clone()
-                    .into_os_string()
-                    .into_string()
-                    .unwrap(),
-            ),
-        ])
-        .args(["-display", "none"])
-        .args(["-serial", "stdio"])
-        // WORKSPACE VirtFS
-        .args([
-            "-virtfs",
-            &format!(
-                "local,path={},mount_tag=0,security_model=none,multidevs=remap,id=hd0",
-                &config.workspace.to_str().unwrap().to_string()
-            ),
-        ]);
-
-    if !config.gpu.is_empty() {
-        for gpu in config.gpu.clone() {
-            cmd.args(["-device", &format!("vfio-pci,rombar=0,host={gpu}")]);
+    cmd.args([
+        "-qmp",
+        &format!("unix:{},server,nowait", qmp_socket_path.display()),
+    ])
+    .args(device_args);
+
+    for gpu in &config.gpu {
+        cmd.args(["-device", &format!("vfio-pci,rombar=0,host={gpu}")]);
+    }
+
+    // The watchdog owns its own QMP connection and only acts once
+    // `config.timeout` elapses, so it's safe to let it run alongside the
+    // blocking wait on the child below.
+    let _watchdog = config
+        .timeout
+        .map(|secs| qmp::spawn_watchdog(qmp_socket_path.clone(), Duration::from_secs(secs)));
+
+    let mut child = cmd.spawn()?;
+
+    if let Some(spec) = &config.cpu_pin {
+        if let Err(e) = pin_vcpus(spec, &qmp_socket_path) {
+            // The child is already running; an error here must not leave
+            // it orphaned with nobody left to reap it.
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
         }
     }
 
-    let status = cmd.status()?;
+    let status = child.wait()?;
     println!("QEMU exit status: {}", status);
 
-    let task_result_file = File::open(config.workspace.join(TASK_RESULT_FILE_NAME))?;
+    if config.pmem {
+        extract_pmem_workspace_results(&workspace_pmem_file, &config.workspace)?;
+    }
+
+    let task_result_file_path = config.workspace.join(TASK_RESULT_FILE_NAME);
+    if !task_result_file_path.exists() {
+        if config.timeout.is_some() {
+            return Err("task timed out before producing a result".into());
+        }
+        return Err(format!("{:?} was not produced by the task", task_result_file_path).into());
+    }
+
+    let task_result_file = File::open(task_result_file_path)?;
     let result: std::result::Result<TaskResult, String> =
         serde_json::from_reader(task_result_file)?;
-    result.map_err(|e| e.into())
+    let task_result: TaskResult = result.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+    task_result
+        .data(config.workspace.to_str().expect("workspace path is valid utf-8"))
+        .map_err(|e| format!("failed to verify task result data: {e}"))?;
+    Ok(task_result)
+}
+
+/// Parse `spec` and pin each vCPU thread to its corresponding host core.
+/// Factored out of `run_qemu` so its error path can be handled as a single
+/// unit: on failure the caller kills the already-spawned QEMU child rather
+/// than leaving it running.
+fn pin_vcpus(spec: &str, qmp_socket_path: &Path) -> Result<()> {
+    let cores = cpu_pin::parse_core_list(spec)?;
+    let mut vm = qmp::VmHandle::connect(qmp_socket_path)?;
+    cpu_pin::pin_vcpus(&mut vm, &cores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_up_rounds_up_to_the_next_multiple() {
+        assert_eq!(align_up(0, PMEM_ALIGNMENT), 0);
+        assert_eq!(align_up(1, PMEM_ALIGNMENT), PMEM_ALIGNMENT);
+        assert_eq!(align_up(PMEM_ALIGNMENT, PMEM_ALIGNMENT), PMEM_ALIGNMENT);
+        assert_eq!(align_up(PMEM_ALIGNMENT + 1, PMEM_ALIGNMENT), 2 * PMEM_ALIGNMENT);
+    }
 }
@@ -0,0 +1,103 @@
+//! CPU pinning of guest vCPU threads to host cores.
+//!
+//! `--smp` allocates vCPUs but leaves the host scheduler free to migrate
+//! their threads, which hurts determinism for proving/compute workloads.
+//! `pin_vcpus` maps each vCPU thread (found via QMP `query-cpus-fast`) to a
+//! host core from an expanded `--cpu-pin` list and calls
+//! `sched_setaffinity`, reproducing vore's affinity handling.
+
+use std::mem::MaybeUninit;
+
+use crate::qmp::VmHandle;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Parse a core list like `0,2,4-7` into an expanded, order-preserving list
+/// of host core indices.
+pub fn parse_core_list(spec: &str) -> Result<Vec<usize>> {
+    let mut cores = Vec::new();
+    for part in spec.split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.trim().parse()?;
+                let end: usize = end.trim().parse()?;
+                cores.extend(start..=end);
+            }
+            None => cores.push(part.parse()?),
+        }
+    }
+    Ok(cores)
+}
+
+/// Pin vCPU `i`'s host thread to the `i`-th core in `cores`, erroring if
+/// `cores` is shorter than the number of vCPUs QEMU reports.
+pub fn pin_vcpus(vm: &mut VmHandle, cores: &[usize]) -> Result<()> {
+    let thread_ids = vm.query_cpus_fast()?;
+    if cores.len() < thread_ids.len() {
+        return Err(format!(
+            "--cpu-pin lists {} core(s) but the guest has {} vcpu(s)",
+            cores.len(),
+            thread_ids.len(),
+        )
+        .into());
+    }
+
+    for (thread_id, &core) in thread_ids.iter().zip(cores) {
+        set_affinity(*thread_id as libc::pid_t, core)?;
+    }
+
+    Ok(())
+}
+
+fn set_affinity(thread_id: libc::pid_t, core: usize) -> Result<()> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(format!(
+            "--cpu-pin core {core} is out of range (must be < {})",
+            libc::CPU_SETSIZE
+        )
+        .into());
+    }
+
+    unsafe {
+        let mut set = MaybeUninit::<libc::cpu_set_t>::zeroed().assume_init();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(thread_id, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_singletons_and_ranges() {
+        assert_eq!(parse_core_list("0,2,4-7").unwrap(), vec![0, 2, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn parses_empty_spec() {
+        assert_eq!(parse_core_list("").unwrap(), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn reversed_range_yields_no_cores() {
+        assert_eq!(parse_core_list("4-1").unwrap(), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn rejects_non_numeric_core() {
+        assert!(parse_core_list("0,not-a-number").is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_core() {
+        assert!(set_affinity(0, libc::CPU_SETSIZE as usize).is_err());
+    }
+}
@@ -2,9 +2,18 @@ use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{BufRead, BufReader, Write};
 use std::path::PathBuf;
+use std::process::Command;
 use std::time::Instant;
 use std::{path::Path, thread::sleep, time::Duration};
 
+pub mod cpu_pin;
+pub mod qemu_config;
+pub mod qmp;
+pub mod result_data;
+pub mod vfio;
+
+pub use result_data::ResultData;
+
 /// MOUNT_TIMEOUT is maximum amount of time to wait for workspace mount to be
 /// present in /proc/mounts.
 const MOUNT_TIMEOUT: Duration = Duration::from_secs(30);
@@ -14,6 +23,18 @@ pub const TASK_RESULT_FILE_NAME: &str = "task_result.json";
 
 pub const WORKSPACE_PATH: &str = "/workspace";
 
+/// Name of the raw file, created inside the workspace directory on the
+/// host, that backs the workspace's virtio-pmem DAX device when `run_qemu`
+/// is launched with `--pmem`.
+pub const WORKSPACE_PMEM_FILE_NAME: &str = "workspace.pmem";
+
+/// Filesystem label `run_qemu` stamps onto the workspace pmem image when it
+/// formats it, and that the guest looks up with `blkid -L`. virtio-pmem
+/// devices are not guaranteed to enumerate as any particular `/dev/pmemN`
+/// (that depends on PCI probe order), so the guest must find its workspace
+/// device by label rather than assuming an index.
+pub const WORKSPACE_PMEM_LABEL: &str = "gevulot-workspace";
+
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 pub type TaskId = String;
 
@@ -27,7 +48,7 @@ pub struct Task {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TaskResult {
     id: TaskId,
-    data: Vec<u8>,
+    data: ResultData,
     files: Vec<String>,
 }
 
@@ -35,7 +56,7 @@ impl Task {
     pub fn result(&self, data: Vec<u8>, files: Vec<String>) -> Result<TaskResult> {
         Ok(TaskResult {
             id: self.id.clone(),
-            data,
+            data: ResultData::encode(data, WORKSPACE_PATH)?,
             files,
         })
     }
@@ -51,6 +72,14 @@ impl Task {
     }
 }
 
+impl TaskResult {
+    /// Read back the result payload, verifying its SHA-256 digest. Fails
+    /// loudly if the data has been corrupted since it was encoded.
+    pub fn data(&self, workspace: &str) -> Result<Vec<u8>> {
+        self.data.decode(workspace)
+    }
+}
+
 fn mount_present(mount_point: &str) -> Result<bool> {
     let file = File::open("/proc/mounts")?;
     let reader = BufReader::new(file);
@@ -65,24 +94,88 @@ fn mount_present(mount_point: &str) -> Result<bool> {
     Ok(false)
 }
 
+/// Look up the workspace pmem device by its `WORKSPACE_PMEM_LABEL`
+/// filesystem label via `blkid`, rather than assuming it enumerates as any
+/// particular `/dev/pmemN`. Returns `None` if `blkid` can't find it (either
+/// it isn't present, or this guest wasn't booted in `--pmem` mode).
+fn discover_workspace_pmem_device() -> Result<Option<PathBuf>> {
+    let output = match Command::new("blkid").args(["-L", WORKSPACE_PMEM_LABEL]).output() {
+        Ok(output) => output,
+        Err(_) => return Ok(None),
+    };
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let device = String::from_utf8(output.stdout)?.trim().to_string();
+    if device.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(PathBuf::from(device)))
+}
+
+/// If the workspace's virtio-pmem DAX device is present, mount it at
+/// `workspace` with `-o dax` and return true. Returns false (without error)
+/// when the device can't be found, so `run` can fall back to waiting for
+/// the virtfs mount instead.
+fn mount_pmem_if_present(workspace: &str) -> Result<bool> {
+    let Some(device) = discover_workspace_pmem_device()? else {
+        return Ok(false);
+    };
+    let device = device.to_str().expect("pmem device path is valid utf-8");
+
+    let status = Command::new("mount").args(["-o", "dax", device, workspace]).status()?;
+    if !status.success() {
+        return Err(format!("failed to mount {device} at {workspace} with dax").into());
+    }
+
+    Ok(true)
+}
+
+/// Flush the workspace filesystem to its backing pmem file and unmount it.
+/// Without this, `task_result.json`/`task_result.bin` written just before
+/// the VM is torn down (watchdog `quit` or `isa-debug-exit`) may still be
+/// sitting in the guest's ext4 journal/page cache rather than the backing
+/// file, so the host's `extract_pmem_workspace_results` loop-mount would
+/// silently see a stale image.
+fn sync_and_unmount_pmem(workspace: &str) -> Result<()> {
+    let status = Command::new("sync").status()?;
+    if !status.success() {
+        return Err(format!("sync failed with {status}").into());
+    }
+
+    let status = Command::new("umount").arg(workspace).status()?;
+    if !status.success() {
+        return Err(format!("umount {workspace} failed with {status}").into());
+    }
+
+    Ok(())
+}
+
 /// run function takes `callback` that is invoked with executable `Task` and
 /// which is expected to return `TaskResult`.
 pub fn run(callback: impl Fn(Task) -> Result<TaskResult>) -> Result<()> {
     let workspace = WORKSPACE_PATH;
 
-    println!("waiting for {workspace} mount to be present");
-    let beginning = Instant::now();
-    loop {
-        if beginning.elapsed() > MOUNT_TIMEOUT {
-            panic!("{} mount timeout", workspace);
+    let pmem_mounted = mount_pmem_if_present(workspace)?;
+    if pmem_mounted {
+        println!("{workspace} mounted via virtio-pmem dax device labeled {WORKSPACE_PMEM_LABEL}");
+    } else {
+        println!("waiting for {workspace} mount to be present");
+        let beginning = Instant::now();
+        loop {
+            if beginning.elapsed() > MOUNT_TIMEOUT {
+                panic!("{} mount timeout", workspace);
+            }
+
+            if mount_present(workspace)? {
+                println!("{workspace} mount is now present");
+                break;
+            }
+
+            sleep(Duration::from_secs(1));
         }
-
-        if mount_present(workspace)? {
-            println!("{workspace} mount is now present");
-            break;
-        }
-
-        sleep(Duration::from_secs(1));
     }
 
     let file = File::open(PathBuf::from(WORKSPACE_PATH).join(TASK_FILE_NAME))?;
@@ -98,5 +191,13 @@ pub fn run(callback: impl Fn(Task) -> Result<TaskResult>) -> Result<()> {
     result_file.flush()?;
     drop(result_file);
 
+    if let Ok(task_result) = &result {
+        task_result.data(WORKSPACE_PATH)?;
+    }
+
+    if pmem_mounted {
+        sync_and_unmount_pmem(workspace)?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,266 @@
+//! Templated/scriptable QEMU command construction.
+//!
+//! The device topology `run_qemu` launches used to be baked into the
+//! binary, so anyone needing a different machine type, extra virtio
+//! devices, a different accelerator, or UEFI/OVMF firmware had to fork the
+//! crate. `QemuCommandBuilder` instead reads a TOML profile describing base
+//! args, per-feature arg fragments, and disk/virtfs templates, then
+//! assembles the final argv from it. The args `run_qemu` used to hardcode
+//! are kept as `QemuProfile::default_profile()` so behavior is unchanged
+//! when no `--qemu-config` is given.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+use serde::Deserialize;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// A named bundle of arg fragments, enabled by passing its name to
+/// `QemuCommandBuilder::build` (e.g. `uefi`, `rng`, `scsi`, `serial`).
+#[derive(Debug, Default, Deserialize)]
+pub struct Feature {
+    pub args: Vec<Vec<String>>,
+}
+
+/// A TOML-described QEMU command profile.
+#[derive(Debug, Default, Deserialize)]
+pub struct QemuProfile {
+    /// Args always included, in the given order.
+    #[serde(default)]
+    pub base: Vec<Vec<String>>,
+    /// Feature arg fragments, keyed by feature name.
+    #[serde(default)]
+    pub features: HashMap<String, Feature>,
+    /// Template for the program image drive, always included: a
+    /// `virtio-pmem` NVDIMM isn't a bootable bus under the default
+    /// SeaBIOS/no-`-kernel` topology, so the program image stays on this
+    /// (typically virtio-scsi) drive regardless of `BuildContext::pmem`.
+    /// `{program}` is substituted.
+    pub disk_template: Vec<String>,
+    /// Template for the workspace mount, used when `BuildContext::pmem` is
+    /// not set. `{workspace}` is substituted.
+    pub virtfs_template: Vec<String>,
+    /// Template for the workspace as a virtio-pmem DAX device, used instead
+    /// of `virtfs_template` when `BuildContext::pmem` is set.
+    /// `{workspace_pmem_file}` and `{workspace_size}` are substituted.
+    #[serde(default)]
+    pub pmem_workspace_template: Vec<String>,
+    /// Optional script hook invoked with `--smp`/`--mem`; its stdout is
+    /// split on newlines and each line is appended as one extra argv entry.
+    /// This is the scriptable escape hatch for topologies templates can't
+    /// express, mirroring vore's `qemu.lua` hook alongside its `global.toml`.
+    #[serde(default)]
+    pub script: Option<String>,
+}
+
+impl QemuProfile {
+    /// Load a profile from a TOML file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// The profile matching the args `run_qemu` used to hardcode.
+    pub fn default_profile() -> Self {
+        let pair = |flag: &str, value: &str| vec![flag.to_string(), value.to_string()];
+
+        let mut features = HashMap::new();
+        features.insert(
+            "rng".to_string(),
+            Feature {
+                args: vec![pair("-device", "virtio-rng-pci")],
+            },
+        );
+        features.insert(
+            "scsi".to_string(),
+            Feature {
+                args: vec![
+                    pair("-device", "virtio-scsi-pci,bus=pci.2,addr=0x0,id=scsi0"),
+                    pair("-device", "scsi-hd,bus=scsi0.0,drive=hd0"),
+                ],
+            },
+        );
+        features.insert(
+            "serial".to_string(),
+            Feature {
+                args: vec![pair("-serial", "stdio")],
+            },
+        );
+
+        Self {
+            base: vec![
+                pair("-machine", "q35"),
+                pair(
+                    "-device",
+                    "pcie-root-port,port=0x10,chassis=1,id=pci.1,bus=pcie.0,multifunction=on,addr=0x3",
+                ),
+                pair(
+                    "-device",
+                    "pcie-root-port,port=0x11,chassis=2,id=pci.2,bus=pcie.0,addr=0x3.0x1",
+                ),
+                pair(
+                    "-device",
+                    "pcie-root-port,port=0x12,chassis=3,id=pci.3,bus=pcie.0,addr=0x3.0x2",
+                ),
+                pair("-vga", "none"),
+                pair("-smp", "{smp}"),
+                pair("-device", "isa-debug-exit"),
+                pair("-m", "{mem}M"),
+                pair("-machine", "accel=kvm:tcg"),
+                pair("-cpu", "max"),
+                pair("-display", "none"),
+            ],
+            features,
+            disk_template: pair("-drive", "file={program},format=raw,if=none,id=hd0,readonly=on"),
+            virtfs_template: pair(
+                "-virtfs",
+                "local,path={workspace},mount_tag=0,security_model=none,multidevs=remap,id=hd0",
+            ),
+            pmem_workspace_template: vec![
+                "-object".to_string(),
+                "memory-backend-file,id=mem-workspace,share=on,mem-path={workspace_pmem_file},size={workspace_size}"
+                    .to_string(),
+                "-device".to_string(),
+                "virtio-pmem-pci,memdev=mem-workspace,id=pmem-workspace".to_string(),
+            ],
+            script: None,
+        }
+    }
+}
+
+/// Values substituted into profile templates in place of `{program}`,
+/// `{workspace}`, `{smp}`, and `{mem}` placeholders.
+pub struct BuildContext<'a> {
+    pub program: &'a str,
+    pub workspace: &'a str,
+    pub smp: u16,
+    pub mem: u32,
+    /// When set, `QemuCommandBuilder::build` swaps `virtfs_template` for
+    /// `pmem_workspace_template`; the program image stays on its normal
+    /// bootable drive either way.
+    pub pmem: bool,
+    /// Path to the raw file backing the workspace pmem device; only used
+    /// in pmem mode.
+    pub workspace_pmem_file: &'a str,
+    /// Size in bytes of `workspace_pmem_file`, already rounded up to the
+    /// memory-backend-file alignment; only used in pmem mode.
+    pub workspace_size: u64,
+}
+
+fn substitute(template: &[String], ctx: &BuildContext) -> Vec<String> {
+    template
+        .iter()
+        .map(|arg| {
+            arg.replace("{program}", ctx.program)
+                .replace("{workspace}", ctx.workspace)
+                .replace("{smp}", &ctx.smp.to_string())
+                .replace("{mem}", &ctx.mem.to_string())
+                .replace("{workspace_pmem_file}", ctx.workspace_pmem_file)
+                .replace("{workspace_size}", &ctx.workspace_size.to_string())
+        })
+        .collect()
+}
+
+/// Assembles a QEMU argv from a `QemuProfile`, the set of enabled feature
+/// names, and the per-invocation values used to fill in templates.
+pub struct QemuCommandBuilder {
+    profile: QemuProfile,
+}
+
+impl QemuCommandBuilder {
+    pub fn new(profile: QemuProfile) -> Self {
+        Self { profile }
+    }
+
+    /// Build the full argv: base args, enabled feature args (in the order
+    /// given), the substituted disk template, the substituted virtfs or
+    /// pmem workspace template, then any args emitted by the optional
+    /// script hook.
+    pub fn build(&self, enabled_features: &[&str], ctx: &BuildContext) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+
+        for fragment in &self.profile.base {
+            args.extend(substitute(fragment, ctx));
+        }
+
+        for name in enabled_features {
+            let feature = self
+                .profile
+                .features
+                .get(*name)
+                .ok_or_else(|| format!("qemu profile has no feature named {name:?}"))?;
+            for fragment in &feature.args {
+                args.extend(substitute(fragment, ctx));
+            }
+        }
+
+        // The program image stays on its normal bootable drive in both
+        // modes; only the workspace channel changes with `ctx.pmem`.
+        args.extend(substitute(&self.profile.disk_template, ctx));
+        if ctx.pmem {
+            args.extend(substitute(&self.profile.pmem_workspace_template, ctx));
+        } else {
+            args.extend(substitute(&self.profile.virtfs_template, ctx));
+        }
+
+        if let Some(script) = &self.profile.script {
+            let output = Command::new(script)
+                .args(["--smp", &ctx.smp.to_string(), "--mem", &ctx.mem.to_string()])
+                .output()?;
+            if !output.status.success() {
+                return Err(format!("qemu config script {script:?} failed").into());
+            }
+            args.extend(String::from_utf8(output.stdout)?.lines().map(String::from));
+        }
+
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_ctx() -> BuildContext<'static> {
+        BuildContext {
+            program: "/tmp/program.img",
+            workspace: "/tmp/workspace",
+            smp: 4,
+            mem: 512,
+            pmem: false,
+            workspace_pmem_file: "/tmp/workspace.pmem",
+            workspace_size: 2 * 1024 * 1024,
+        }
+    }
+
+    #[test]
+    fn build_substitutes_placeholders_and_picks_virtfs_template() {
+        let builder = QemuCommandBuilder::new(QemuProfile::default_profile());
+        let args = builder.build(&["rng", "scsi", "serial"], &test_ctx()).unwrap();
+
+        assert!(args.contains(&"file=/tmp/program.img,format=raw,if=none,id=hd0,readonly=on".to_string()));
+        assert!(args.iter().any(|a| a == "local,path=/tmp/workspace,mount_tag=0,security_model=none,multidevs=remap,id=hd0"));
+        assert!(!args.iter().any(|a| a.contains("memory-backend-file")));
+    }
+
+    #[test]
+    fn build_picks_pmem_workspace_template_when_pmem_is_set() {
+        let builder = QemuCommandBuilder::new(QemuProfile::default_profile());
+        let ctx = BuildContext { pmem: true, ..test_ctx() };
+        let args = builder.build(&["rng", "scsi", "serial"], &ctx).unwrap();
+
+        assert!(args
+            .iter()
+            .any(|a| a.contains("mem-path=/tmp/workspace.pmem") && a.contains(&format!("size={}", ctx.workspace_size))));
+        assert!(!args.iter().any(|a| a.contains("-virtfs")));
+    }
+
+    #[test]
+    fn build_errors_on_unknown_feature() {
+        let builder = QemuCommandBuilder::new(QemuProfile::default_profile());
+        let err = builder.build(&["does-not-exist"], &test_ctx()).unwrap_err();
+        assert!(err.to_string().contains("does-not-exist"));
+    }
+}
@@ -0,0 +1,110 @@
+//! QMP (QEMU Machine Protocol) control socket.
+//!
+//! `run_qemu` launches the guest with a Unix-domain QMP socket so the shim
+//! can observe and control its lifecycle instead of only blocking on
+//! `Command::status()`. This mirrors the persistent control socket the vore
+//! VM wrapper keeps alongside its child process.
+
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use qapi::{qmp, Qmp};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Name of the QMP unix socket file created inside the workspace directory.
+pub const QMP_SOCKET_NAME: &str = "qmp.sock";
+
+/// How long to keep retrying the initial connection to the QMP socket,
+/// since QEMU may not have created it yet right after the child is spawned.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Grace period given to the guest to shut down after a graceful
+/// `system_powerdown` before the watchdog escalates to `quit`.
+const POWERDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Handle to a running guest's QMP control socket.
+pub struct VmHandle {
+    qmp: Qmp<qapi::Stream<UnixStream, UnixStream>>,
+}
+
+impl VmHandle {
+    /// Connect to `socket_path` and perform the QMP capabilities handshake.
+    pub fn connect(socket_path: &Path) -> Result<Self> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let stream = loop {
+            match UnixStream::connect(socket_path) {
+                Ok(stream) => break stream,
+                Err(_) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let read_half = stream.try_clone()?;
+        let stream = qapi::Stream::new(read_half, stream);
+        let mut qmp = Qmp::from_stream(stream);
+        qmp.handshake()?;
+        Ok(Self { qmp })
+    }
+
+    /// Query the guest's current run status (e.g. "running", "paused").
+    pub fn query_status(&mut self) -> Result<qmp::StatusInfo> {
+        Ok(self.qmp.execute(&qmp::query_status {})?)
+    }
+
+    /// Query the host thread ID backing each vCPU, in vCPU index order.
+    /// QMP does not guarantee `query-cpus-fast` returns its array already
+    /// sorted by `cpu-index`, so it's sorted explicitly before mapping.
+    pub fn query_cpus_fast(&mut self) -> Result<Vec<i64>> {
+        let mut cpus = self.qmp.execute(&qmp::query_cpus_fast {})?;
+        cpus.sort_by_key(|cpu| cpu.cpu_index);
+        Ok(cpus.into_iter().map(|cpu| cpu.thread_id).collect())
+    }
+
+    /// Request a graceful ACPI shutdown.
+    pub fn system_powerdown(&mut self) -> Result<()> {
+        self.qmp.execute(&qmp::system_powerdown {})?;
+        Ok(())
+    }
+
+    /// Force the guest to quit immediately.
+    pub fn quit(&mut self) -> Result<()> {
+        self.qmp.execute(&qmp::quit {})?;
+        Ok(())
+    }
+}
+
+/// Spawn a watchdog thread enforcing `timeout`: once it elapses, request a
+/// graceful `system_powerdown`, then escalate to a forced `quit` if the
+/// guest hasn't exited within the grace window. Errors talking to QMP are
+/// logged and otherwise ignored, since the caller's own wait on the child
+/// process is always the final word on whether the guest has exited.
+pub fn spawn_watchdog(socket_path: std::path::PathBuf, timeout: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        thread::sleep(timeout);
+
+        let mut handle = match VmHandle::connect(&socket_path) {
+            Ok(handle) => handle,
+            Err(e) => {
+                eprintln!("watchdog: failed to connect to qmp socket: {e}");
+                return;
+            }
+        };
+
+        eprintln!("watchdog: task timeout of {timeout:?} reached, requesting graceful shutdown");
+        if let Err(e) = handle.system_powerdown() {
+            eprintln!("watchdog: system_powerdown failed: {e}");
+        }
+
+        thread::sleep(POWERDOWN_GRACE);
+        if handle.query_status().is_ok() {
+            eprintln!("watchdog: guest still alive after grace period, forcing quit");
+            if let Err(e) = handle.quit() {
+                eprintln!("watchdog: quit failed: {e}");
+            }
+        }
+    })
+}
@@ -0,0 +1,172 @@
+//! Binary-efficient, integrity-checked task result payloads.
+//!
+//! `TaskResult.data` used to be a `Vec<u8>` serialized through
+//! `serde_json`, so every result byte became a comma-separated integer — a
+//! 10 MB output ballooned to roughly 40 MB of JSON and was slow to parse.
+//! `ResultData` instead base64-encodes small payloads inline and writes
+//! larger ones to a sibling file referenced by offset/length, with a
+//! SHA-256 digest stored in the JSON descriptor either way so corruption
+//! over the shared workspace mount is caught rather than silently returned.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Name of the sibling file external result payloads are written to.
+pub const TASK_RESULT_DATA_FILE_NAME: &str = "task_result.bin";
+
+/// Payloads at or below this size are base64-encoded inline in
+/// `task_result.json`; larger ones are written out-of-band.
+const INLINE_MAX_SIZE: usize = 4096;
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(d)?;
+        STANDARD.decode(encoded.as_bytes()).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A task result payload, encoded either inline or out-of-band depending
+/// on its size.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "encoding", rename_all = "snake_case")]
+pub enum ResultData {
+    Inline {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+        sha256: String,
+    },
+    External {
+        file: String,
+        offset: u64,
+        length: u64,
+        sha256: String,
+    },
+}
+
+impl ResultData {
+    /// Encode `data`, writing it to `task_result.bin` in `workspace` if it
+    /// is larger than `INLINE_MAX_SIZE`.
+    pub fn encode(data: Vec<u8>, workspace: &str) -> Result<Self> {
+        let sha256 = sha256_hex(&data);
+
+        if data.len() <= INLINE_MAX_SIZE {
+            return Ok(ResultData::Inline { data, sha256 });
+        }
+
+        let path = Path::new(workspace).join(TASK_RESULT_DATA_FILE_NAME);
+        let mut file = File::create(&path)?;
+        file.write_all(&data)?;
+        file.flush()?;
+
+        Ok(ResultData::External {
+            file: TASK_RESULT_DATA_FILE_NAME.to_string(),
+            offset: 0,
+            length: data.len() as u64,
+            sha256,
+        })
+    }
+
+    /// Read the payload back, verifying its SHA-256 digest against the one
+    /// recorded at encode time. Fails loudly on any mismatch.
+    pub fn decode(&self, workspace: &str) -> Result<Vec<u8>> {
+        let (data, sha256) = match self {
+            ResultData::Inline { data, sha256 } => (data.clone(), sha256),
+            ResultData::External {
+                file,
+                offset,
+                length,
+                sha256,
+            } => {
+                let mut f = File::open(Path::new(workspace).join(file))?;
+                f.seek(SeekFrom::Start(*offset))?;
+                let mut data = vec![0u8; *length as usize];
+                f.read_exact(&mut data)?;
+                (data, sha256)
+            }
+        };
+
+        let actual = sha256_hex(&data);
+        if &actual != sha256 {
+            return Err(format!(
+                "task result data is corrupt: expected sha256 {sha256}, got {actual}"
+            )
+            .into());
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// A fresh scratch directory per test, so `External` encoding (which
+    /// writes a sibling file) has somewhere real to write to.
+    fn test_workspace() -> std::path::PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "gevulot-shim-result-data-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn inline_roundtrips_small_payload() {
+        let workspace = test_workspace();
+        let workspace = workspace.to_str().unwrap();
+
+        let data = b"hello".to_vec();
+        let encoded = ResultData::encode(data.clone(), workspace).unwrap();
+        assert!(matches!(encoded, ResultData::Inline { .. }));
+        assert_eq!(encoded.decode(workspace).unwrap(), data);
+    }
+
+    #[test]
+    fn external_roundtrips_large_payload() {
+        let workspace = test_workspace();
+        let workspace = workspace.to_str().unwrap();
+
+        let data = vec![0x42u8; INLINE_MAX_SIZE + 1];
+        let encoded = ResultData::encode(data.clone(), workspace).unwrap();
+        assert!(matches!(encoded, ResultData::External { .. }));
+        assert_eq!(encoded.decode(workspace).unwrap(), data);
+    }
+
+    #[test]
+    fn decode_fails_loudly_on_corruption() {
+        let workspace = test_workspace();
+        let workspace_str = workspace.to_str().unwrap();
+
+        let size = INLINE_MAX_SIZE + 1;
+        let encoded = ResultData::encode(vec![0x7u8; size], workspace_str).unwrap();
+
+        // Same length as the original payload, so decode reaches the
+        // digest check instead of failing to read enough bytes.
+        std::fs::write(workspace.join(TASK_RESULT_DATA_FILE_NAME), vec![0x8u8; size]).unwrap();
+
+        let err = encoded.decode(workspace_str).unwrap_err();
+        assert!(err.to_string().contains("corrupt"));
+    }
+}
@@ -0,0 +1,122 @@
+//! VFIO auto-bind/unbind of passthrough PCI devices.
+//!
+//! `--gpu` used to just emit `vfio-pci,host=<addr>` devices and assume the
+//! PCI device was already bound to `vfio-pci`, which usually isn't true on
+//! a fresh host. `bind_vfio` instead unbinds whatever driver is currently
+//! attached (refusing those in `AUTO_UNBIND_BLACKLIST`) and binds `vfio-pci`
+//! in its place, the same bind dance vore performs around its VFIO slots.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Drivers that must never be auto-unbound: doing so live is known to crash
+/// the host.
+pub const AUTO_UNBIND_BLACKLIST: &[&str] = &["nvidia", "amdgpu"];
+
+const PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices";
+const VFIO_DRIVER_PATH: &str = "/sys/bus/pci/drivers/vfio-pci";
+const BIND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The driver a PCI device was bound to before `bind_vfio` reassigned it,
+/// so it can be restored with `restore` once QEMU exits.
+pub struct OriginalBinding {
+    address: String,
+    driver: Option<String>,
+}
+
+fn driver_link_path(address: &str) -> PathBuf {
+    Path::new(PCI_DEVICES_PATH).join(address).join("driver")
+}
+
+fn current_driver(address: &str) -> Result<Option<String>> {
+    let link = driver_link_path(address);
+    if !link.exists() {
+        return Ok(None);
+    }
+    let target = fs::read_link(&link)?;
+    Ok(target.file_name().map(|name| name.to_string_lossy().into_owned()))
+}
+
+fn vendor_device_id(address: &str) -> Result<String> {
+    let base = Path::new(PCI_DEVICES_PATH).join(address);
+    let vendor = fs::read_to_string(base.join("vendor"))?;
+    let device = fs::read_to_string(base.join("device"))?;
+    Ok(format!(
+        "{} {}",
+        vendor.trim().trim_start_matches("0x"),
+        device.trim().trim_start_matches("0x"),
+    ))
+}
+
+fn wait_for_driver(address: &str, want: &str) -> Result<()> {
+    let deadline = Instant::now() + BIND_TIMEOUT;
+    loop {
+        if current_driver(address)?.as_deref() == Some(want) {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            return Err(format!("{address} did not bind to {want} within {BIND_TIMEOUT:?}").into());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Bind `address` to `vfio-pci`, unbinding whatever driver (if any) is
+/// currently attached; a driver in `AUTO_UNBIND_BLACKLIST` is left bound and
+/// an error is returned instead. Returns the original binding so it can be restored
+/// with `OriginalBinding::restore` once QEMU exits.
+pub fn bind_vfio(address: &str) -> Result<OriginalBinding> {
+    let original = current_driver(address)?;
+
+    match &original {
+        Some(driver) if driver == "vfio-pci" => {
+            return Ok(OriginalBinding {
+                address: address.to_string(),
+                driver: original,
+            });
+        }
+        Some(driver) if AUTO_UNBIND_BLACKLIST.contains(&driver.as_str()) => {
+            return Err(format!(
+                "refusing to auto-unbind {address} from blacklisted driver {driver:?}; unbind it manually first"
+            )
+            .into());
+        }
+        Some(driver) => {
+            fs::write(Path::new("/sys/bus/pci/drivers").join(driver).join("unbind"), address)?;
+        }
+        None => {}
+    }
+
+    // Ignore the error: the vendor/device ID may already be registered
+    // with vfio-pci from a previous run.
+    let _ = fs::write(Path::new(VFIO_DRIVER_PATH).join("new_id"), vendor_device_id(address)?);
+
+    wait_for_driver(address, "vfio-pci")?;
+
+    Ok(OriginalBinding {
+        address: address.to_string(),
+        driver: original,
+    })
+}
+
+impl OriginalBinding {
+    /// Restore the driver that was bound to this address before `bind_vfio`
+    /// reassigned it, if any.
+    pub fn restore(&self) -> Result<()> {
+        let Some(driver) = &self.driver else {
+            return Ok(());
+        };
+        if driver == "vfio-pci" {
+            return Ok(());
+        }
+        fs::write(Path::new(VFIO_DRIVER_PATH).join("unbind"), &self.address)?;
+        fs::write(
+            Path::new("/sys/bus/pci/drivers").join(driver).join("bind"),
+            &self.address,
+        )?;
+        Ok(())
+    }
+}